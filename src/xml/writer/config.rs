@@ -0,0 +1,127 @@
+//! Contains `EmitterConfig`, the configuration struct used to customize `EventWriter`.
+
+/// Which character is used to delimit attribute values.
+#[deriving(Clone, Copy, PartialEq, Eq)]
+pub enum AttributeQuoteStyle {
+    /// Attribute values are wrapped in single quotes, e.g. `a='b'`.
+    SingleQuote,
+    /// Attribute values are wrapped in double quotes, e.g. `a="b"`.
+    DoubleQuote
+}
+
+impl AttributeQuoteStyle {
+    #[inline]
+    pub fn quote_char(self) -> char {
+        match self {
+            SingleQuote => '\'',
+            DoubleQuote => '"'
+        }
+    }
+}
+
+/// Controls whether the emitter wraps its output in ANSI color escapes, for use when writing
+/// directly to a terminal. Modeled on the `--color` flag rustc's diagnostic emitter uses.
+#[deriving(Clone, Copy, PartialEq, Eq)]
+pub enum ColorConfig {
+    /// Always colorize output, regardless of what `target` turns out to be.
+    Always,
+    /// Never colorize output; `emit_*` calls produce the exact same bytes as without this
+    /// option.
+    Never,
+    /// Colorize output only when the actual output target is known to be a terminal. Since the
+    /// emitter writes to a generic `Writer` and can't introspect it, this is `false` until the
+    /// caller tells the emitter otherwise via `Emitter::set_target_is_tty` (e.g. after wrapping
+    /// `std::io::stdio::stdout()`); piped or redirected output never embeds escape codes.
+    Auto
+}
+
+impl ColorConfig {
+    /// Resolves this setting to a concrete on/off flag. `target_is_tty` is the caller's answer
+    /// for whether the actual output target is a terminal; pass `false` when that isn't known,
+    /// which is the only safe default for a generic `Writer`.
+    pub fn should_colorize(self, target_is_tty: bool) -> bool {
+        match self {
+            Always => true,
+            Never => false,
+            Auto => target_is_tty
+        }
+    }
+}
+
+/// Checks whether this process' stdout is attached to a terminal. Useful as the `is_tty`
+/// argument to `Emitter::set_target_is_tty` when the emitter is known to be writing to stdout.
+#[cfg(unix)]
+pub fn stdout_is_tty() -> bool {
+    extern "C" {
+        fn isatty(fd: i32) -> i32;
+    }
+    unsafe { isatty(1) != 0 }
+}
+
+/// Checks whether this process' stdout is attached to a terminal. Useful as the `is_tty`
+/// argument to `Emitter::set_target_is_tty` when the emitter is known to be writing to stdout.
+#[cfg(not(unix))]
+pub fn stdout_is_tty() -> bool {
+    false
+}
+
+/// Configuration struct for the XML emitter (`writer::EventWriter`).
+///
+/// This struct is passed to `EventWriter::new_with_config` and controls the
+/// overall shape of the emitted document: pretty-printing, the XML
+/// declaration, and attribute quoting.
+#[deriving(Clone)]
+pub struct EmitterConfig {
+    /// Line separator used to separate lines in pretty-printed output. Default: `"\n"`.
+    pub line_separator: String,
+
+    /// A string which will be used for a single level of indentation when pretty-printing.
+    /// Default: two spaces (`"  "`).
+    pub indent_string: String,
+
+    /// Whether to write the XML declaration (`<?xml version="1.0" encoding="utf-8"?>`) at the
+    /// start of the document if it hasn't been emitted explicitly. Default: true.
+    pub write_document_declaration: bool,
+
+    /// Which character is used to delimit attribute values. Default: `DoubleQuote`.
+    pub attribute_quote_style: AttributeQuoteStyle,
+
+    /// Whether character data whose escaping would be dense should automatically be wrapped in
+    /// a `CDATA` section instead of being escaped. Default: false.
+    pub cdata_auto: bool,
+
+    /// The fraction of characters in a character data chunk that must require escaping before
+    /// `cdata_auto` switches to a `CDATA` section. Ignored unless `cdata_auto` is true.
+    /// Default: 0.15.
+    pub cdata_auto_threshold: f32,
+
+    /// The maximum column a start tag may reach before its remaining attributes are wrapped
+    /// onto their own indented lines. `None` disables wrapping. Has no effect when
+    /// `indent_string` is empty. Default: `None`.
+    pub max_line_width: Option<uint>,
+
+    /// Whether to wrap emitted markup and text in ANSI color escapes. Default: `Never`.
+    pub color: ColorConfig,
+}
+
+impl EmitterConfig {
+    /// Creates a new config with default values.
+    #[inline]
+    pub fn new() -> EmitterConfig {
+        EmitterConfig {
+            line_separator: String::from_str("\n"),
+            indent_string: String::from_str("  "),
+            write_document_declaration: true,
+            attribute_quote_style: DoubleQuote,
+            cdata_auto: false,
+            cdata_auto_threshold: 0.15,
+            max_line_width: None,
+            color: Never,
+        }
+    }
+}
+
+impl Default for EmitterConfig {
+    #[inline]
+    fn default() -> EmitterConfig { EmitterConfig::new() }
+}
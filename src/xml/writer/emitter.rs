@@ -45,26 +45,44 @@ pub struct Emitter {
     config: EmitterConfig,
 
     nst: NamespaceStack,
+    next_auto_prefix: uint,
 
     indent_level: uint,
     indent_stack: Vec<u8>,
+    column: uint,
+    color_enabled: bool,
 
     start_document_emitted: bool
 }
 
 pub fn new(config: EmitterConfig) -> Emitter {
+    // The actual output target isn't known yet (and may never be introspectable, since it's a
+    // generic `Writer`), so `ColorConfig::Auto` starts out as plain XML. Callers that know their
+    // target is a real terminal can confirm that via `Emitter::set_target_is_tty`.
+    let color_enabled = config.color.should_colorize(false);
+
     Emitter {
         config: config,
 
         nst: NamespaceStack::empty(),
+        next_auto_prefix: 0,
 
         indent_level: 0,
         indent_stack: vec!(),
+        column: 0,
+        color_enabled: color_enabled,
 
         start_document_emitted: false
     }
 }
 
+static ANSI_RESET: &'static str = "\x1b[0m";
+static ANSI_TAG: &'static str = "\x1b[34m";
+static ANSI_ATTR_NAME: &'static str = "\x1b[36m";
+static ANSI_ATTR_VALUE: &'static str = "\x1b[32m";
+static ANSI_PI: &'static str = "\x1b[35m";
+static ANSI_TEXT: &'static str = "\x1b[37m";
+
 macro_rules! io_try(
     ($e:expr) => (
         match $e {
@@ -99,6 +117,66 @@ static WROTE_NOTHING: u8 = 0;
 static WROTE_MARKUP: u8 = 1;
 static WROTE_TEXT: u8 = 2;
 
+// Escapes character data: `&` and `<` always, and `>` only where it would
+// otherwise close a "]]>" sequence, which is illegal outside of CDATA.
+fn escape_characters(content: &str) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut prev_two: (char, char) = ('\0', '\0');
+    for c in content.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' if prev_two == (']', ']') => result.push_str("&gt;"),
+            _ => result.push(c)
+        }
+        prev_two = (prev_two.1, c);
+    }
+    result
+}
+
+// Decides whether a chunk of character data is dense enough with characters
+// that require escaping to be worth wrapping in a CDATA section instead.
+// Content containing a literal "]]>" is never auto-CDATA'd, since splitting
+// it would undermine the readability this mode is meant to provide.
+fn should_use_cdata(content: &str, threshold: f32) -> bool {
+    if content.is_empty() || content.contains("]]>") {
+        return false;
+    }
+
+    let mut escapes = 0u;
+    let mut total = 0u;
+    for c in content.chars() {
+        total += 1;
+        match c {
+            // Matches exactly what escape_characters escapes unconditionally; '>' is
+            // context-dependent (only near "]]") so it isn't counted here.
+            '<' | '&' => escapes += 1,
+            _ => {}
+        }
+    }
+
+    (escapes as f32) / (total as f32) > threshold
+}
+
+// Escapes an attribute value: `&` and `<` as in character data, plus the
+// active quote character and newline/tab/CR as numeric character references
+// so the value round-trips exactly regardless of how it is re-parsed.
+fn escape_attribute_value(content: &str, quote: char) -> String {
+    let mut result = String::with_capacity(content.len());
+    for c in content.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '\n' => result.push_str("&#10;"),
+            '\t' => result.push_str("&#9;"),
+            '\r' => result.push_str("&#13;"),
+            c if c == quote => result.push_str(format!("&#{};", c as uint).as_slice()),
+            _ => result.push(c)
+        }
+    }
+    result
+}
+
 impl Emitter {
     /// Returns current state of namespaces.
     #[inline]
@@ -106,6 +184,13 @@ impl Emitter {
         & self.nst
     }
 
+    /// Tells the emitter whether the output target it writes to is actually a terminal. Only
+    /// affects anything when `color` is `ColorConfig::Auto`; `Always` and `Never` don't depend
+    /// on the target and are unaffected.
+    pub fn set_target_is_tty(&mut self, is_tty: bool) {
+        self.color_enabled = self.config.color.should_colorize(is_tty);
+    }
+
     #[inline]
     fn wrote_text(&self) -> bool {
         *self.indent_stack.last().unwrap() & WROTE_TEXT > 0
@@ -136,6 +221,7 @@ impl Emitter {
         for i in iter::range(0, level) {
             io_try!(target.write_str(self.config.indent_string.as_slice()));
         }
+        self.column = level * self.config.indent_string.len();
         Ok(())
     }
 
@@ -175,8 +261,9 @@ impl Emitter {
     fn after_end_element(&mut self) {
         if self.indent_level > 0 {
             self.indent_level -= 1;
-            self.indent_stack.pop();
         }
+        self.indent_stack.pop();
+        self.nst.pop();
         self.set_wrote_markup();
     }
 
@@ -184,6 +271,16 @@ impl Emitter {
         self.set_wrote_text();
     }
 
+    // Wraps `content` in the given ANSI escape sequence when colorized output is enabled for
+    // this emitter; otherwise returns it unchanged, byte for byte.
+    fn colorize(&self, code: &str, content: &str) -> String {
+        if self.color_enabled {
+            format!("{}{}{}", code, content, ANSI_RESET)
+        } else {
+            String::from_str(content)
+        }
+    }
+
     pub fn emit_start_document<W: Writer>(&mut self, target: &mut W, version: XmlVersion, encoding: &str, standalone: Option<bool>) -> EmitterResult<()> {
         if self.start_document_emitted {
             return Err(error(DocumentStartAlreadyEmitted, "Document start is already emitted"));
@@ -212,9 +309,11 @@ impl Emitter {
     pub fn emit_processing_instruction<W: Writer>(&mut self, target: &mut W, name: &str, data: Option<&str>) -> EmitterResult<()> {
         try!(self.check_document_started(target));
 
+        let rendered_name = self.colorize(ANSI_PI, name);
+
         wrapped_with!(before_markup(target) and after_markup,
             io_chain!(
-                write!(target, "<?{}", name),
+                write!(target, "<?{}", rendered_name),
 
                 if_present!(data, write!(target, " {}", data)),
 
@@ -223,14 +322,76 @@ impl Emitter {
         )
     }
 
+    // Finds a prefix already bound (in any enclosing scope, including the one
+    // just pushed for the current element) to the given URI. The `xml` and
+    // `xmlns` URIs are reserved and always resolve to their fixed prefixes.
+    fn find_bound_prefix(&self, uri: &str) -> Option<String> {
+        if uri == common::NS_XML_URI {
+            return Some(String::from_str(common::NS_XML_PREFIX));
+        }
+        if uri == common::NS_XMLNS_URI {
+            return Some(String::from_str(common::NS_XMLNS_PREFIX));
+        }
+
+        let merged = self.nst.squash();
+        for (prefix, bound_uri) in merged.0.iter() {
+            if prefix.as_slice() != common::NS_NO_PREFIX && bound_uri.as_slice() == uri {
+                return Some(prefix.clone());
+            }
+        }
+        None
+    }
+
+    // Resolves `name` against the namespace stack, which must already have
+    // the element's own namespace scope pushed onto it. If `name` carries a
+    // namespace URI but no prefix and nothing in scope binds that URI yet, a
+    // fresh `ns0`, `ns1`, ... prefix is generated and bound into the current
+    // (topmost) scope. Returns the (possibly reprefixed) name, plus the
+    // freshly-bound (prefix, uri) pair when one was generated, so the caller
+    // can also emit its declaration.
+    fn resolve_element_name(&mut self, name: &Name) -> (Name, Option<(String, String)>) {
+        let uri = match name.namespace {
+            Some(ref uri) => uri.clone(),
+            None => return (name.clone(), None)
+        };
+
+        if name.prefix.is_some() {
+            return (name.clone(), None);
+        }
+
+        if self.nst.get(common::NS_NO_PREFIX) == Some(uri.as_slice()) {
+            return (name.clone(), None);
+        }
+
+        if let Some(prefix) = self.find_bound_prefix(uri.as_slice()) {
+            let resolved = Name { local_name: name.local_name.clone(), namespace: Some(uri), prefix: Some(prefix) };
+            return (resolved, None);
+        }
+
+        let prefix = format!("ns{}", self.next_auto_prefix);
+        self.next_auto_prefix += 1;
+        self.nst.put(prefix.as_slice(), uri.as_slice());
+
+        let resolved = Name { local_name: name.local_name.clone(), namespace: Some(uri.clone()), prefix: Some(prefix.clone()) };
+        (resolved, Some((prefix, uri)))
+    }
+
     fn emit_start_element_initial<W: Writer>(&mut self, target: &mut W, name: &Name, attributes: &[Attribute], namespace: &Namespace) -> EmitterResult<()> {
         try!(self.check_document_started(target));
 
         try!(self.before_start_element(target));
 
-        io_try!(write!(target, "<{}", name.to_str_proper()));
+        let enclosing = self.nst.squash();
+        self.nst.push(namespace.clone());
+
+        let (resolved_name, auto_decl) = self.resolve_element_name(name);
+
+        let tag = resolved_name.to_str_proper();
+        let rendered_tag = self.colorize(ANSI_TAG, tag.as_slice());
+        io_try!(write!(target, "<{}", rendered_tag));
+        self.column += 1 + tag.len();
 
-        try!(self.emit_namespace_attributes(target, namespace));
+        try!(self.emit_namespace_attributes(target, namespace, &enclosing, auto_decl));
 
         self.emit_attributes(target, attributes)
     }
@@ -238,44 +399,271 @@ impl Emitter {
     pub fn emit_empty_element<W: Writer>(&mut self, target: &mut W, name: &Name, attributes: &[Attribute], namespace: &Namespace) -> EmitterResult<()> {
         try!(self.emit_start_element_initial(target, name, attributes, namespace));
 
-        io_wrap(write!(target, "/>"))
+        let result = io_wrap(write!(target, "/>"));
+        self.after_end_element();
+        result
     }
 
     pub fn emit_start_element<W: Writer>(&mut self, target: &mut W, name: &Name, attributes: &[Attribute], namespace: &Namespace) -> EmitterResult<()> {
         try!(self.emit_start_element_initial(target, name, attributes, namespace));
 
-        try!(self.check_document_started(target));
+        io_try!(write!(target, ">"));
 
-        wrapped_with!(before_start_element(target) and after_start_element, {
-            io_try!(write!(target, "<{}", name.to_str_proper()));
+        self.after_start_element();
 
-            self.emit_namespace_attributes(target, namespace);
+        Ok(())
+    }
+
+    // `enclosing` is the merged namespace stack as it stood before the
+    // current element's scope was pushed; it is used to avoid re-declaring
+    // prefix -> URI bindings that are already in effect. `auto_decl` is the
+    // binding `resolve_element_name` may have generated for the element name
+    // itself, which isn't part of `namespace` and so needs to be emitted too.
+    pub fn emit_namespace_attributes<W: Writer>(&mut self, target: &mut W, namespace: &Namespace, enclosing: &Namespace, auto_decl: Option<(String, String)>) -> EmitterResult<()> {
+        for (prefix, uri) in namespace.0.iter() {
+            if uri.as_slice() == common::NS_XML_URI || uri.as_slice() == common::NS_XMLNS_URI {
+                continue;
+            }
+            if enclosing.get(prefix.as_slice()) == Some(uri.as_slice()) {
+                continue;
+            }
+            try!(self.write_ns_declaration(target, prefix.as_slice(), uri.as_slice()));
+        }
+
+        if let Some((prefix, uri)) = auto_decl {
+            try!(self.write_ns_declaration(target, prefix.as_slice(), uri.as_slice()));
+        }
 
-            self.emit_attributes(target, attributes)
-        })
+        Ok(())
+    }
+
+    // Renders a single `name="value"` pair (colorized if enabled), shared by ordinary
+    // attributes and xmlns declarations so both quote, color, and measure it identically.
+    // Returns the rendered text plus its plain (uncolorized) length, since ANSI escapes take up
+    // bytes but no columns on screen.
+    fn render_name_value(&self, name: &str, value: &str, quote: char) -> (String, uint) {
+        let plain_len = name.len() + 1 + 2 + value.len(); // name '=' quote value quote
+
+        let rendered = format!("{}={}{}{}",
+            self.colorize(ANSI_ATTR_NAME, name),
+            quote,
+            self.colorize(ANSI_ATTR_VALUE, value),
+            quote
+        );
+
+        (rendered, plain_len)
     }
 
-    pub fn emit_namespace_attributes<W: Writer>(&mut self, target: &mut W, namespace: &Namespace) -> EmitterResult<()> {
+    fn write_ns_declaration<W: Writer>(&mut self, target: &mut W, prefix: &str, uri: &str) -> EmitterResult<()> {
+        let quote = self.config.attribute_quote_style.quote_char();
+        let escaped_uri = escape_attribute_value(uri, quote);
+
+        let name = if prefix == common::NS_NO_PREFIX {
+            String::from_str("xmlns")
+        } else {
+            format!("xmlns:{}", prefix)
+        };
+
+        let (rendered, plain_len) = self.render_name_value(name.as_slice(), escaped_uri.as_slice(), quote);
+
+        io_try!(write!(target, " "));
+        io_try!(target.write_str(rendered.as_slice()));
+        self.column += 1 + plain_len;
+
         Ok(())
     }
 
-    pub fn emit_attributes<W: Writer>(&mut self, target: &mut W, namespace: &[Attribute]) -> EmitterResult<()> {
+    pub fn emit_attributes<W: Writer>(&mut self, target: &mut W, attributes: &[Attribute]) -> EmitterResult<()> {
+        let quote = self.config.attribute_quote_style.quote_char();
+        let wrapping_enabled = self.config.indent_string.len() > 0 && self.config.max_line_width.is_some();
+        let max_width = self.config.max_line_width.unwrap_or(0);
+        let mut wrapped = false;
+
+        for attr in attributes.iter() {
+            let name = attr.name.to_str_proper();
+            let value = escape_attribute_value(attr.value.as_slice(), quote);
+
+            let (rendered, plain_len) = self.render_name_value(name.as_slice(), value.as_slice(), quote);
+
+            if wrapping_enabled && (wrapped || self.column + 1 + plain_len > max_width) {
+                wrapped = true;
+                try!(self.write_newline(target, self.indent_level + 1));
+            } else {
+                io_try!(write!(target, " "));
+                self.column += 1;
+            }
+
+            io_try!(target.write_str(rendered.as_slice()));
+            self.column += plain_len;
+        }
+
         Ok(())
     }
 
+    // Resolves `name` for a closing tag using the namespace scope that is
+    // still live from the matching start tag (popped only afterwards, by
+    // `after_end_element`).
+    fn resolve_closing_name(&self, name: &Name) -> Name {
+        match name.namespace {
+            None => name.clone(),
+            Some(ref uri) => {
+                if name.prefix.is_some() {
+                    return name.clone();
+                }
+                if self.nst.get(common::NS_NO_PREFIX) == Some(uri.as_slice()) {
+                    return name.clone();
+                }
+                match self.find_bound_prefix(uri.as_slice()) {
+                    Some(prefix) => Name { local_name: name.local_name.clone(), namespace: Some(uri.clone()), prefix: Some(prefix) },
+                    None => name.clone()
+                }
+            }
+        }
+    }
+
     pub fn emit_end_element<W: Writer>(&mut self, target: &mut W, name: &Name) -> EmitterResult<()> {
+        try!(self.before_end_element(target));
+
+        let resolved_name = self.resolve_closing_name(name);
+        let tag = resolved_name.to_str_proper();
+        let rendered_tag = self.colorize(ANSI_TAG, tag.as_slice());
+
+        io_try!(write!(target, "</{}>", rendered_tag));
+
+        self.after_end_element();
+
         Ok(())
     }
 
+    // A literal "]]>" cannot appear inside a CDATA section; splitting it
+    // across two adjacent sections keeps the content losslessly intact.
+    fn write_cdata_section<W: Writer>(&mut self, target: &mut W, content: &str) -> EmitterResult<()> {
+        let escaped = content.replace("]]>", "]]]]><![CDATA[>");
+        let body = format!("<![CDATA[{}]]>", escaped);
+
+        io_try!(target.write_str(self.colorize(ANSI_TEXT, body.as_slice()).as_slice()));
+        self.advance_column(body.as_slice());
+
+        Ok(())
+    }
+
+    // Advances `self.column` by the screen width of `written`, which may itself contain
+    // newlines (e.g. character data or a CDATA section carrying a literal "\n"): in that case
+    // only the text after the last newline still occupies the current line.
+    fn advance_column(&mut self, written: &str) {
+        match written.rfind('\n') {
+            Some(pos) => self.column = written.slice_from(pos + 1).len(),
+            None => self.column += written.len()
+        }
+    }
+
     pub fn emit_cdata<W: Writer>(&mut self, target: &mut W, content: &str) -> EmitterResult<()> {
+        try!(self.before_markup(target));
+
+        try!(self.write_cdata_section(target, content));
+
+        self.after_text();
+
         Ok(())
     }
 
     pub fn emit_characters<W: Writer>(&mut self, target: &mut W, content: &str) -> EmitterResult<()> {
+        if self.config.cdata_auto && should_use_cdata(content, self.config.cdata_auto_threshold) {
+            // No before_markup here: this is still character content, just represented as a
+            // CDATA section, so indentation must stay suppressed exactly like the escaped path
+            // below (a newline here would become part of the element's text).
+            try!(self.write_cdata_section(target, content));
+        } else {
+            let escaped = escape_characters(content);
+            io_try!(target.write_str(self.colorize(ANSI_TEXT, escaped.as_slice()).as_slice()));
+            self.advance_column(escaped.as_slice());
+        }
+
+        self.after_text();
+
         Ok(())
     }
 
     pub fn emit_whitespace<W: Writer>(&mut self, target: &mut W, content: &str) -> EmitterResult<()> {
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use common::{Name, Namespace};
+
+    use writer::config::EmitterConfig;
+
+    use super::{new, escape_characters, escape_attribute_value};
+
+    #[test]
+    fn escapes_ampersand_and_less_than_always() {
+        assert_eq!(escape_characters("a < b & c"), String::from_str("a &lt; b &amp; c"));
+    }
+
+    #[test]
+    fn escapes_greater_than_only_when_closing_a_cdata_terminator() {
+        assert_eq!(escape_characters("a > b"), String::from_str("a > b"));
+        assert_eq!(escape_characters("]]>"), String::from_str("]]&gt;"));
+        assert_eq!(escape_characters("x]]>y"), String::from_str("x]]&gt;y"));
+    }
+
+    #[test]
+    fn escapes_active_quote_and_control_characters_in_attribute_values() {
+        assert_eq!(escape_attribute_value("a\"b", '"'), String::from_str("a&#34;b"));
+        assert_eq!(escape_attribute_value("a'b", '\''), String::from_str("a&#39;b"));
+        // The inactive quote character is left alone.
+        assert_eq!(escape_attribute_value("a'b", '"'), String::from_str("a'b"));
+        assert_eq!(escape_attribute_value("a\nb\tc\rd", '"'), String::from_str("a&#10;b&#9;c&#13;d"));
+    }
+
+    #[test]
+    fn splits_a_literal_cdata_terminator_across_two_sections() {
+        let mut out = io::MemWriter::new();
+        let mut emitter = new(EmitterConfig::new());
+
+        match emitter.write_cdata_section(&mut out, "a]]>b") {
+            Ok(()) => {},
+            Err(_) => panic!("write_cdata_section returned an error")
+        }
+
+        let written = String::from_utf8(out.unwrap()).unwrap();
+        assert_eq!(written, String::from_str("<![CDATA[a]]]]><![CDATA[>b]]>"));
+    }
+
+    #[test]
+    fn reuses_an_already_bound_prefix_for_the_same_uri() {
+        let mut emitter = new(EmitterConfig::new());
+
+        let mut ns = Namespace::empty();
+        ns.put("a", "urn:example:a");
+        emitter.nst.push(ns);
+
+        let name = Name { local_name: String::from_str("foo"), namespace: Some(String::from_str("urn:example:a")), prefix: None };
+        let (resolved, auto_decl) = emitter.resolve_element_name(&name);
+
+        assert_eq!(resolved.prefix, Some(String::from_str("a")));
+        assert!(auto_decl.is_none());
+    }
+
+    #[test]
+    fn generates_a_fresh_prefix_when_none_is_bound_and_reuses_it_on_the_next_call() {
+        let mut emitter = new(EmitterConfig::new());
+        emitter.nst.push(Namespace::empty());
+
+        let name = Name { local_name: String::from_str("foo"), namespace: Some(String::from_str("urn:example:b")), prefix: None };
+
+        let (first, first_decl) = emitter.resolve_element_name(&name);
+        assert_eq!(first.prefix, Some(String::from_str("ns0")));
+        assert_eq!(first_decl, Some((String::from_str("ns0"), String::from_str("urn:example:b"))));
+
+        // The binding just made is visible to a second lookup for the same URI, so no
+        // second prefix is minted.
+        let (second, second_decl) = emitter.resolve_element_name(&name);
+        assert_eq!(second.prefix, Some(String::from_str("ns0")));
+        assert!(second_decl.is_none());
+    }
 }
\ No newline at end of file